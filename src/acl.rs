@@ -0,0 +1,274 @@
+// Copyright 2017 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Casbin-style access-control enforcer used to decide which discovered
+//! services are allowed to be exposed to the cloud.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use utils::RuntimeError;
+
+/// Action requested against a given service.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    Expose,
+}
+
+impl Action {
+    /// Get string representation of the action.
+    fn as_str(&self) -> &str {
+        match self {
+            &Action::Expose => "expose",
+        }
+    }
+}
+
+/// Effect of a matched policy rule.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Effect {
+    Allow,
+    Deny,
+}
+
+/// A single `(subject, object, action) -> effect` policy rule.
+struct Rule {
+    subject: String,
+    object:  String,
+    action:  String,
+    effect:  Effect,
+}
+
+impl Rule {
+    /// Check whether this rule matches a given request. The subject and
+    /// object patterns may be the wildcard `"*"`.
+    fn matches(&self, subject: &str, object: &str, action: &str) -> bool {
+        (self.subject == "*" || self.subject == subject) &&
+        (self.object  == "*" || self.object  == object)  &&
+        self.action == action
+    }
+}
+
+/// Compiled access-control policy evaluated on `(subject, object, action)`
+/// triples. Unless a policy file is configured, the enforcer allows
+/// everything so existing deployments keep working.
+pub struct Enforcer {
+    rules:      Vec<Rule>,
+    force_deny: bool,
+}
+
+impl Enforcer {
+    /// Create an enforcer that allows every request (i.e. no policy file
+    /// configured).
+    pub fn allow_all() -> Enforcer {
+        Enforcer {
+            rules:      Vec::new(),
+            force_deny: false,
+        }
+    }
+
+    /// Create an enforcer that denies every request. This is the safe
+    /// fallback used when a configured policy file cannot be loaded; a
+    /// broken or unreadable policy must never be interpreted as "expose
+    /// everything".
+    pub fn deny_all() -> Enforcer {
+        Enforcer {
+            rules:      Vec::new(),
+            force_deny: true,
+        }
+    }
+
+    /// Load an enforcer from a policy file.
+    ///
+    /// Every non-empty, non-comment line of the file is expected to be in
+    /// the form `subject, object, action, allow|deny`. Lines starting with
+    /// `#` are treated as comments.
+    pub fn load<P: AsRef<str>>(path: P) -> Result<Enforcer, RuntimeError> {
+        let path = path.as_ref();
+
+        let file = File::open(path)
+            .map_err(|err| RuntimeError::from(
+                format!("unable to open ACL policy file `{}`: {}", path, err)))?;
+
+        let mut rules = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|err| RuntimeError::from(
+                format!("unable to read ACL policy file `{}`: {}", path, err)))?;
+
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split(',')
+                .map(|field| field.trim())
+                .collect::<Vec<_>>();
+
+            if fields.len() != 4 {
+                return Err(RuntimeError::from(
+                    format!("malformed ACL policy line in `{}`: \"{}\"", path, line)));
+            }
+
+            let effect = match fields[3] {
+                "allow" => Effect::Allow,
+                "deny"  => Effect::Deny,
+                other   => return Err(RuntimeError::from(
+                    format!("unknown ACL effect \"{}\" in `{}`", other, path))),
+            };
+
+            rules.push(Rule {
+                subject: fields[0].to_string(),
+                object:  fields[1].to_string(),
+                action:  fields[2].to_string(),
+                effect:  effect,
+            });
+        }
+
+        Ok(Enforcer {
+            rules:      rules,
+            force_deny: false,
+        })
+    }
+
+    /// Evaluate the `(subject, object, action)` triple against the policy.
+    /// The first matching rule wins; if no rule matches, the request is
+    /// denied whenever at least one rule is loaded and allowed otherwise
+    /// (i.e. an empty policy keeps the previous "expose everything"
+    /// behaviour).
+    pub fn enforce(&self, subject: &str, object: &str, action: Action) -> bool {
+        if self.force_deny {
+            return false;
+        }
+
+        if self.rules.is_empty() {
+            return true;
+        }
+
+        let action = action.as_str();
+
+        for rule in &self.rules {
+            if rule.matches(subject, object, action) {
+                return rule.effect == Effect::Allow;
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{Action, Enforcer};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    /// Write `contents` into a fresh temporary file and return its path.
+    /// The caller is responsible for removing it.
+    fn write_policy(contents: &str) -> String {
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = ::std::env::temp_dir()
+            .join(format!("arrow-client-acl-test-{}-{}.csv", ::std::process::id(), id));
+        let path = path.to_str().unwrap().to_string();
+
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn allow_all_allows_everything() {
+        let enforcer = Enforcer::allow_all();
+
+        assert!(enforcer.enforce("aa:bb:cc:dd:ee:ff", "192.168.0.1:554", Action::Expose));
+    }
+
+    #[test]
+    fn deny_all_denies_everything() {
+        let enforcer = Enforcer::deny_all();
+
+        assert!(!enforcer.enforce("aa:bb:cc:dd:ee:ff", "192.168.0.1:554", Action::Expose));
+    }
+
+    #[test]
+    fn exact_match_allows() {
+        let path = write_policy("aa:bb:cc:dd:ee:ff, 192.168.0.1:554, expose, allow\n");
+        let enforcer = Enforcer::load(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(enforcer.enforce("aa:bb:cc:dd:ee:ff", "192.168.0.1:554", Action::Expose));
+    }
+
+    #[test]
+    fn wildcard_subject_matches_any_subject() {
+        let path = write_policy("*, 192.168.0.1:554, expose, allow\n");
+        let enforcer = Enforcer::load(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(enforcer.enforce("11:22:33:44:55:66", "192.168.0.1:554", Action::Expose));
+    }
+
+    #[test]
+    fn unmatched_request_is_denied_when_policy_is_nonempty() {
+        let path = write_policy("aa:bb:cc:dd:ee:ff, 192.168.0.1:554, expose, allow\n");
+        let enforcer = Enforcer::load(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(!enforcer.enforce("11:22:33:44:55:66", "192.168.0.2:554", Action::Expose));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_ignored() {
+        let path = write_policy("# comment\n\n  \naa:bb:cc:dd:ee:ff, 192.168.0.1:554, expose, deny\n");
+        let enforcer = Enforcer::load(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(!enforcer.enforce("aa:bb:cc:dd:ee:ff", "192.168.0.1:554", Action::Expose));
+    }
+
+    #[test]
+    fn malformed_line_is_rejected() {
+        let path = write_policy("aa:bb:cc:dd:ee:ff, 192.168.0.1:554, expose\n");
+        let result = Enforcer::load(&path);
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unknown_effect_is_rejected() {
+        let path = write_policy("aa:bb:cc:dd:ee:ff, 192.168.0.1:554, expose, maybe\n");
+        let result = Enforcer::load(&path);
+
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn missing_policy_file_is_rejected() {
+        let result = Enforcer::load("/nonexistent/path/to/acl.csv");
+
+        assert!(result.is_err());
+    }
+}
@@ -16,9 +16,16 @@ use std::fmt;
 use std::io;
 
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::Write;
-use std::sync::{Arc, Mutex};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use time;
+
+use acl::{Action, Enforcer};
+
+use tls;
 
 use config::ApplicationConfig;
 
@@ -37,6 +44,36 @@ use native_tls::TlsConnector;
 
 use uuid::Uuid;
 
+/// Wrap an I/O error with the path and operation that failed.
+fn io_context(verb: &str, path: &str, err: io::Error) -> RuntimeError {
+    RuntimeError::from(format!("failed to {} `{}`: {}", verb, path, err))
+}
+
+/// Escape a string for embedding into a JSON string literal, including
+/// control characters (the journal `reason` field is arbitrary caller text
+/// -- e.g. another error's `Display` output -- and may itself contain
+/// embedded newlines, which would otherwise corrupt the JSON Lines
+/// framing).
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"'  => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
 /// Arrow service connection state.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ConnectionState {
@@ -69,17 +106,66 @@ struct ApplicationContextData {
     scanning:    bool,
     scan_report: ScanReport,
     conn_state:  ConnectionState,
+    enforcer:    Enforcer,
+    acl_version: usize,
 }
 
 impl ApplicationContextData {
     /// Take a given application config and create application context data.
     fn new(config: ApplicationConfig) -> ApplicationContextData {
+        let mut logger = config.get_logger();
+        let version    = config.get_version();
+        let enforcer   = Self::load_enforcer(&config, &mut logger);
+
         ApplicationContextData {
-            logger:      config.get_logger(),
+            logger:      logger,
             config:      config,
             scanning:    false,
             scan_report: ScanReport::new(),
             conn_state:  ConnectionState::Disconnected,
+            enforcer:    enforcer,
+            acl_version: version,
+        }
+    }
+
+    /// Load the access-control enforcer from the policy file referenced by
+    /// the current configuration (if any). A policy file that cannot be
+    /// loaded or parsed is surfaced as a WARN and the enforcer fails
+    /// *closed* (denying every service) rather than silently exposing
+    /// everything, since that would defeat the point of configuring a
+    /// policy in the first place.
+    fn load_enforcer(config: &ApplicationConfig, logger: &mut BoxedLogger) -> Enforcer {
+        match config.get_acl_policy_file() {
+            Some(path) => {
+                let res = Enforcer::load(path);
+
+                match res {
+                    Ok(enforcer) => enforcer,
+                    Err(err) => {
+                        let res: Result<(), RuntimeError> = Err(err);
+
+                        utils::result_or_log(
+                            logger,
+                            Severity::WARN,
+                            "unable to load ACL policy, denying all services until it is fixed",
+                            res);
+
+                        Enforcer::deny_all()
+                    },
+                }
+            },
+            None => Enforcer::allow_all(),
+        }
+    }
+
+    /// Reload the enforcer whenever the configuration has been updated
+    /// since it was last compiled.
+    fn reload_enforcer_if_needed(&mut self) {
+        let version = self.config.get_version();
+
+        if version != self.acl_version {
+            self.enforcer    = Self::load_enforcer(&self.config, &mut self.logger);
+            self.acl_version = version;
         }
     }
 
@@ -113,9 +199,42 @@ impl ApplicationContextData {
         self.logger.clone()
     }
 
-    /// Get TLS connector.
+    /// Get TLS connector. Without a configured client identity this is
+    /// `ApplicationConfig`'s own connector. When mutual TLS is configured,
+    /// the connector is built from a default `TlsConnector` builder with the
+    /// client identity installed, so the cloud side can authenticate the
+    /// device by certificate rather than only by UUID/password; settings of
+    /// `ApplicationConfig`'s own connector are not carried over in that case.
     fn get_tls_connector(&self) -> Result<TlsConnector, RuntimeError> {
-        self.config.get_tls_connector()
+        let identity_path = match self.config.get_tls_identity_file() {
+            Some(path) => path,
+            None       => return self.config.get_tls_connector()
+                .map_err(|err| RuntimeError::from(
+                    format!("failed to build TLS connector: {}", err))),
+        };
+
+        let passphrase = self.config.get_tls_identity_passphrase()
+            .unwrap_or_else(|| "".to_string());
+
+        let identity = tls::load_identity(identity_path, &passphrase)?;
+
+        let mut builder = TlsConnector::builder()
+            .map_err(|err| RuntimeError::from(
+                format!("failed to initialize TLS connector builder: {}", err)))?;
+
+        builder.identity(identity)
+            .map_err(|err| RuntimeError::from(
+                format!("failed to install TLS client identity `{}`: {}", identity_path, err)))?;
+
+        builder.build()
+            .map_err(|err| RuntimeError::from(
+                format!("failed to build TLS connector: {}", err)))
+    }
+
+    /// Verify the DER encoding of the Arrow service's leaf certificate
+    /// against the configured certificate pins (if any).
+    fn verify_peer_certificate(&self, cert_der: &[u8]) -> Result<(), RuntimeError> {
+        tls::verify_pin(cert_der, self.config.get_tls_certificate_pins())
     }
 
     /// Set the state of the network scanner thread.
@@ -135,7 +254,20 @@ impl ApplicationContextData {
 
     /// Update the scan report.
     fn update_scan_report(&mut self, report: ScanReport) {
-        self.scan_report = report;
+        self.reload_enforcer_if_needed();
+
+        let enforcer = &self.enforcer;
+
+        let allowed = report.services()
+            .iter()
+            .filter(|service| enforcer.enforce(
+                &service.mac().to_string(),
+                &service.address().to_string(),
+                Action::Expose))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.scan_report = ScanReport::from_services(allowed);
     }
 
     /// Get read-only reference to the service table.
@@ -148,11 +280,24 @@ impl ApplicationContextData {
         self.config.reset_service_table()
     }
 
-    /// Set connection state.
+    /// Get the current connection state.
+    fn get_connection_state(&self) -> ConnectionState {
+        self.conn_state
+    }
+
+    /// Set connection state without recording a reason for the transition.
     fn set_connection_state(&mut self, state: ConnectionState) {
+        self.set_connection_state_with_reason(state, None)
+    }
+
+    /// Set connection state, recording an optional human-readable reason
+    /// for the transition in the connection-state journal.
+    fn set_connection_state_with_reason(&mut self, state: ConnectionState, reason: Option<String>) {
+        let from = self.conn_state;
+
         self.conn_state = state;
 
-        let res = self.save_connection_state();
+        let res = self.save_connection_state(from, state, reason);
 
         utils::result_or_log(
             &mut self.logger,
@@ -161,11 +306,100 @@ impl ApplicationContextData {
             res);
     }
 
-    /// Save connection state into the file.
-    fn save_connection_state(&self) -> Result<(), io::Error> {
-        let mut file = File::create(self.config.get_connection_state_file())?;
+    /// Append a connection-state transition into the journal file and
+    /// refresh the single-word "latest state" file kept for backward
+    /// compatibility.
+    fn save_connection_state(
+        &self,
+        from: ConnectionState,
+        to: ConnectionState,
+        reason: Option<String>) -> Result<(), RuntimeError> {
+        let latest_path = self.config.get_connection_state_file();
+
+        let mut latest = File::create(latest_path)
+            .map_err(|err| io_context("write connection-state file", latest_path, err))?;
 
-        writeln!(&mut file, "{}", self.conn_state)?;
+        writeln!(&mut latest, "{}", to)
+            .map_err(|err| io_context("write connection-state file", latest_path, err))?;
+
+        let entry = format!(
+            "{{\"timestamp\":\"{}\",\"from\":\"{}\",\"to\":\"{}\",\"reason\":{}}}",
+            time::now_utc().rfc3339(),
+            from,
+            to,
+            match reason {
+                Some(ref reason) => format!("\"{}\"", json_escape(reason)),
+                None             => "null".to_string(),
+            });
+
+        let journal_path = self.config.get_connection_state_journal_file();
+
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(journal_path)
+            .map_err(|err| io_context("open connection-state journal", journal_path, err))?;
+
+        writeln!(&mut journal, "{}", entry)
+            .map_err(|err| io_context("write connection-state journal", journal_path, err))?;
+
+        self.rotate_connection_state_journal(journal_path)
+    }
+
+    /// Trim the connection-state journal down to the configured maximum
+    /// number of lines and/or bytes, dropping the oldest entries first.
+    fn rotate_connection_state_journal(&self, journal_path: &str) -> Result<(), RuntimeError> {
+        let max_lines = self.config.get_connection_state_journal_max_lines();
+        let max_bytes = self.config.get_connection_state_journal_max_bytes();
+
+        if max_lines.is_none() && max_bytes.is_none() {
+            return Ok(());
+        }
+
+        let lines = {
+            let file = File::open(journal_path)
+                .map_err(|err| io_context("read connection-state journal", journal_path, err))?;
+
+            BufReader::new(file)
+                .lines()
+                .collect::<Result<Vec<String>, io::Error>>()
+                .map_err(|err| io_context("read connection-state journal", journal_path, err))?
+        };
+
+        // index of the first line to keep; starts at 0 (keep everything)
+        // and is pushed forward by whichever bound is tighter
+        let mut keep_from = 0;
+
+        if let Some(max_lines) = max_lines {
+            if lines.len() > max_lines {
+                keep_from = lines.len() - max_lines;
+            }
+        }
+
+        if let Some(max_bytes) = max_bytes {
+            let mut total = 0;
+
+            for (i, line) in lines.iter().enumerate().rev() {
+                total += line.len() + 1;
+
+                if total > max_bytes {
+                    keep_from = keep_from.max(i + 1);
+                    break;
+                }
+            }
+        }
+
+        if keep_from == 0 {
+            return Ok(());
+        }
+
+        let mut file = File::create(journal_path)
+            .map_err(|err| io_context("rotate connection-state journal", journal_path, err))?;
+
+        for line in &lines[keep_from..] {
+            writeln!(&mut file, "{}", line)
+                .map_err(|err| io_context("rotate connection-state journal", journal_path, err))?;
+        }
 
         Ok(())
     }
@@ -173,127 +407,175 @@ impl ApplicationContextData {
     /// Add given services into the service table.
     fn update_services<I>(&mut self, services: I)
         where I: IntoIterator<Item=Service> {
-        self.config.update_services(services)
+        self.reload_enforcer_if_needed();
+
+        let enforcer = &self.enforcer;
+
+        let allowed = services.into_iter()
+            .filter(|service| enforcer.enforce(
+                &service.mac().to_string(),
+                &service.address().to_string(),
+                Action::Expose))
+            .collect::<Vec<_>>();
+
+        self.config.update_services(allowed)
     }
 }
 
 /// Application context.
 #[derive(Clone)]
 pub struct ApplicationContext {
-    data: Arc<Mutex<ApplicationContextData>>,
+    data:          Arc<Mutex<ApplicationContextData>>,
+    poison_logged: Arc<AtomicBool>,
 }
 
 impl ApplicationContext {
     /// Take a given application config and create a new application context.
     pub fn new(config: ApplicationConfig) -> ApplicationContext {
         ApplicationContext {
-            data: Arc::new(Mutex::new(ApplicationContextData::new(config)))
+            data:          Arc::new(Mutex::new(ApplicationContextData::new(config))),
+            poison_logged: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Lock the inner application context data, recovering from a poisoned
+    /// mutex instead of propagating the panic. A panic in one subsystem
+    /// while holding the lock must not cascade into every other thread
+    /// that touches the application context. The mutex stays poisoned for
+    /// its lifetime once this happens, so the WARN is only logged once
+    /// rather than on every subsequent call.
+    fn lock(&self) -> MutexGuard<ApplicationContextData> {
+        match self.data.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                let guard = poisoned.into_inner();
+
+                if !self.poison_logged.swap(true, Ordering::Relaxed) {
+                    let mut logger = guard.logger.clone();
+
+                    let res: Result<(), &str> = Err(
+                        "a thread holding the application context lock panicked");
+
+                    utils::result_or_log(
+                        &mut logger,
+                        Severity::WARN,
+                        "recovered from a poisoned application context mutex",
+                        res);
+                }
+
+                guard
+            },
         }
     }
 
     /// Get current version of the configuration.
     pub fn get_config_version(&self) -> usize {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_config_version()
     }
 
     /// Get Arrow Client UUID.
     pub fn get_arrow_uuid(&self) -> Uuid {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_arrow_uuid()
     }
 
     /// Get Arrow Client password.
     pub fn get_arrow_password(&self) -> Uuid {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_arrow_password()
     }
 
     /// Get Arrow Client MAC address.
     pub fn get_arrow_mac_address(&self) -> MacAddr {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_arrow_mac_address()
     }
 
     /// Check if the application is in the diagnostic mode.
     pub fn get_diagnostic_mode(&self) -> bool {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_diagnostic_mode()
     }
 
     /// Get application logger.
     pub fn get_logger(&self) -> BoxedLogger {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_logger()
     }
 
     /// Get TLS connector.
     pub fn get_tls_connector(&self) -> Result<TlsConnector, RuntimeError> {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_tls_connector()
     }
 
+    /// Verify the DER encoding of the Arrow service's leaf certificate
+    /// against the configured certificate pins (if any).
+    pub fn verify_peer_certificate(&self, cert_der: &[u8]) -> Result<(), RuntimeError> {
+        self.lock()
+            .verify_peer_certificate(cert_der)
+    }
+
     /// Set the state of the network scanner thread.
     pub fn set_scanning(&mut self, scanning: bool) {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .set_scanning(scanning)
     }
 
     /// Check if the network scanner thread is running right now.
     pub fn is_scanning(&self) -> bool {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .is_scanning()
     }
 
     /// Get the last scan report.
     pub fn get_scan_report(&self) -> ScanReport {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_scan_report()
     }
 
     /// Update the scan report.
     pub fn update_scan_report(&mut self, report: ScanReport) {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .update_scan_report(report)
     }
 
     /// Get read-only reference to the service table.
     pub fn get_service_table(&self) -> SharedServiceTableRef {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .get_service_table()
     }
 
     /// Reset service table.
     pub fn reset_service_table(&mut self) {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .reset_service_table()
     }
 
+    /// Get the current connection state.
+    pub fn get_connection_state(&self) -> ConnectionState {
+        self.lock()
+            .get_connection_state()
+    }
+
     /// Set connection state.
     pub fn set_connection_state(&mut self, state: ConnectionState) {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .set_connection_state(state)
     }
 
+    /// Set connection state, recording an optional human-readable reason
+    /// for the transition in the connection-state journal.
+    pub fn set_connection_state_with_reason(&mut self, state: ConnectionState, reason: Option<String>) {
+        self.lock()
+            .set_connection_state_with_reason(state, reason)
+    }
+
     /// Add given services into the service table.
     pub fn update_services<I>(&mut self, services: I)
         where I: IntoIterator<Item=Service> {
-        self.data.lock()
-            .unwrap()
+        self.lock()
             .update_services(services)
     }
 }
\ No newline at end of file
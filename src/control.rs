@@ -0,0 +1,215 @@
+// Copyright 2017 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local control/management interface for runtime introspection and
+//! commands. The listener is a Unix domain socket on *nix systems and a
+//! loopback TCP socket on Windows, speaking a simple line-based
+//! request/response protocol.
+
+use std::io::{BufRead, BufReader, Write};
+use std::process;
+use std::thread;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+#[cfg(not(unix))]
+use std::net::{TcpListener, TcpStream};
+
+use context::ApplicationContext;
+use context::json_escape;
+
+use net::arrow::proto::{ScanReport, Service};
+
+use svc_table::SharedServiceTableRef;
+
+use utils::RuntimeError;
+
+/// A single control connection, abstracted over the underlying transport.
+#[cfg(unix)]
+type Connection = UnixStream;
+
+#[cfg(not(unix))]
+type Connection = TcpStream;
+
+/// Address the control listener is bound to.
+pub enum ControlAddr {
+    /// Path to a Unix domain socket.
+    #[cfg(unix)]
+    Unix(String),
+    /// Loopback TCP port.
+    #[cfg(not(unix))]
+    Tcp(u16),
+}
+
+/// Spawn the control service in a background thread. The given application
+/// context is cloned for every accepted connection, so callers keep using
+/// their own clone as usual.
+pub fn spawn(context: ApplicationContext, addr: ControlAddr) -> Result<(), RuntimeError> {
+    let listener = bind(&addr)?;
+
+    thread::spawn(move || {
+        serve(listener, context);
+    });
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bind(addr: &ControlAddr) -> Result<UnixListener, RuntimeError> {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let ControlAddr::Unix(ref path) = *addr;
+
+    // remove a stale socket file left over from a previous run
+    let _ = fs::remove_file(path);
+
+    // the control protocol is unauthenticated and lets a peer steer
+    // scanning and wipe the service table, so the socket must never be
+    // reachable under its final name with default (umask-derived)
+    // permissions, even briefly; bind under a private temporary name,
+    // chmod it, then atomically rename it into place so the restrictive
+    // mode is already in effect by the time `path` exists
+    let tmp_path = format!("{}.{}.tmp", path, process::id());
+
+    let _ = fs::remove_file(&tmp_path);
+
+    let listener = UnixListener::bind(&tmp_path)
+        .map_err(|err| RuntimeError::from(
+            format!("unable to bind control socket `{}`: {}", tmp_path, err)))?;
+
+    fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))
+        .map_err(|err| RuntimeError::from(
+            format!("unable to set permissions on control socket `{}`: {}", tmp_path, err)))?;
+
+    fs::rename(&tmp_path, path)
+        .map_err(|err| RuntimeError::from(
+            format!("unable to install control socket `{}`: {}", path, err)))?;
+
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+fn bind(addr: &ControlAddr) -> Result<TcpListener, RuntimeError> {
+    let ControlAddr::Tcp(port) = *addr;
+
+    TcpListener::bind(("127.0.0.1", port))
+        .map_err(|err| RuntimeError::from(
+            format!("unable to bind control socket on 127.0.0.1:{}: {}", port, err)))
+}
+
+#[cfg(unix)]
+fn serve(listener: UnixListener, context: ApplicationContext) {
+    for stream in listener.incoming() {
+        if let Ok(stream) = stream {
+            let context = context.clone();
+            thread::spawn(move || handle_connection(stream, context));
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn serve(listener: TcpListener, context: ApplicationContext) {
+    for stream in listener.incoming() {
+        if let Ok(stream) = stream {
+            let context = context.clone();
+            thread::spawn(move || handle_connection(stream, context));
+        }
+    }
+}
+
+/// Handle a single control connection: read newline-terminated commands
+/// and reply with a newline-terminated response until the peer hangs up.
+fn handle_connection(stream: Connection, mut context: ApplicationContext) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_)     => return,
+    };
+
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_)   => break,
+        };
+
+        let response = dispatch(&mut context, line.trim());
+
+        if writeln!(&mut writer, "{}", response).is_err() {
+            break;
+        }
+    }
+}
+
+/// Serialize a slice of services as a JSON array. Neither `ScanReport` nor
+/// `SharedServiceTableRef` implements `Serialize`, so the services are
+/// encoded by hand from their public accessors; used by both `GET_SERVICES`
+/// and `GET_SCAN_REPORT` so the control protocol has one consistent wire
+/// format instead of mixing Debug dumps with hand-rolled JSON.
+fn services_to_json<'a, I: IntoIterator<Item=&'a Service>>(services: I) -> String {
+    let services = services.into_iter()
+        .map(|service| format!(
+            "{{\"mac\":\"{}\",\"address\":\"{}\"}}",
+            json_escape(&service.mac().to_string()),
+            json_escape(&service.address().to_string())))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!("{{\"services\":[{}]}}", services)
+}
+
+/// Serialize a scan report as JSON.
+fn scan_report_to_json(report: &ScanReport) -> String {
+    services_to_json(report.services().iter())
+}
+
+/// Serialize the service table as JSON.
+fn service_table_to_json(table: &SharedServiceTableRef) -> String {
+    services_to_json(table.services().iter())
+}
+
+/// Dispatch a single command line against the given context and return the
+/// response to be sent back to the client.
+fn dispatch(context: &mut ApplicationContext, command: &str) -> String {
+    let mut parts = command.splitn(2, ' ');
+
+    let command = parts.next().unwrap_or("").to_uppercase();
+    let arg     = parts.next().unwrap_or("").trim();
+
+    match command.as_str() {
+        "GET_STATE" => format!("{}", context.get_connection_state()),
+        "GET_SCAN_REPORT" => scan_report_to_json(&context.get_scan_report()),
+        "GET_SERVICES" => service_table_to_json(&context.get_service_table()),
+        "RESCAN" => {
+            // RESCAN drops whatever services are currently published and
+            // then sets the scanning flag, the same as SET_SCANNING true;
+            // it does not start a scan by itself.
+            context.reset_service_table();
+            context.set_scanning(true);
+            "OK".to_string()
+        },
+        "RESET_SERVICES" => {
+            context.reset_service_table();
+            "OK".to_string()
+        },
+        "SET_SCANNING" => match arg {
+            "true"  => { context.set_scanning(true);  "OK".to_string() },
+            "false" => { context.set_scanning(false); "OK".to_string() },
+            _       => "ERROR expected \"true\" or \"false\"".to_string(),
+        },
+        _ => format!("ERROR unknown command \"{}\"", command),
+    }
+}
@@ -0,0 +1,3 @@
+mod acl;
+pub mod control;
+pub mod tls;
@@ -0,0 +1,71 @@
+// Copyright 2017 click2stream, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for mutual-TLS client identities and server certificate pinning.
+//! `ApplicationContextData::get_tls_connector()` uses `load_identity()` to
+//! install a client certificate on the `TlsConnector` it builds, and
+//! `ApplicationContextData::verify_peer_certificate()` uses `verify_pin()`
+//! once the Arrow connection has the peer's leaf certificate.
+
+use std::fs;
+
+use native_tls::Identity;
+
+use sha2::{Digest, Sha256};
+
+use utils::RuntimeError;
+
+/// Load a PKCS#12 client identity from a file so it can be installed on a
+/// `TlsConnector` via `TlsConnectorBuilder::identity()`, enabling mutual
+/// TLS where the cloud side authenticates the device by certificate.
+pub fn load_identity(path: &str, passphrase: &str) -> Result<Identity, RuntimeError> {
+    let data = fs::read(path)
+        .map_err(|err| RuntimeError::from(
+            format!("unable to read TLS identity file `{}`: {}", path, err)))?;
+
+    Identity::from_pkcs12(&data, passphrase)
+        .map_err(|err| RuntimeError::from(
+            format!("malformed TLS identity file `{}`: {}", path, err)))
+}
+
+/// Compute the lower-case hex-encoded SHA-256 fingerprint of a DER-encoded
+/// certificate. Uses the portable `sha2` crate rather than hard-depending
+/// on OpenSSL, so pinning works the same way on every platform `native_tls`
+/// supports (Secure Transport on macOS, SChannel on Windows, OpenSSL on
+/// Linux).
+pub fn fingerprint(cert_der: &[u8]) -> String {
+    let digest = Sha256::digest(cert_der);
+
+    digest.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>()
+}
+
+/// Verify that the DER encoding of the peer's leaf certificate matches one
+/// of the configured pins. An empty pin set means pinning is disabled and
+/// every certificate is accepted (the previous behaviour).
+pub fn verify_pin(cert_der: &[u8], pins: &[String]) -> Result<(), RuntimeError> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+
+    let actual = fingerprint(cert_der);
+
+    if pins.iter().any(|pin| pin.eq_ignore_ascii_case(&actual)) {
+        Ok(())
+    } else {
+        Err(RuntimeError::from(
+            format!("server certificate fingerprint `{}` does not match any configured pin", actual)))
+    }
+}